@@ -7,46 +7,493 @@ The decompressed chunks aren't guaranteed to align to the compressed ones.
 If the response is plaintext then no additional work is carried out.
 Chunks are just passed along.
 
-If the response is gzip, then the chunks are decompressed into a buffer.
-Slices of that buffer are emitted as new chunks.
+If the response is gzip, brotli, deflate, or zstd, then the chunks are decompressed
+into a buffer. Slices of that buffer are emitted as new chunks.
+
+A response may also stack several of these encodings (e.g. `Content-Encoding: gzip, br`),
+in which case the decoders are chained together in the reverse of the order they're listed.
+
+Chunked responses may carry a trailer section after their final chunk; once the body's
+`Stream` reaches end-of-stream, those trailers are available via `Decoder::trailers()`.
+
+To guard against decompression bombs, a `Decoder` can be given a limit on the total number
+of decompressed bytes it will emit; exceeding it fails the stream instead of continuing to
+inflate the response.
+
+Gzip decompression can optionally be offloaded to a blocking thread pool instead of running
+inline in `poll_next`, so a large response doesn't stall the executor it's decoded on.
+
+## Wiring this up from `Client`/`ClientBuilder`/`Response`
+
+This module only builds and drives decoders; it doesn't decide when to use them. Callers
+that advertise support, set limits, or hand trailers back to users are expected to go
+through these entry points:
+
+* `Client`/`ClientBuilder` should send [`accept_encoding_value()`] as the outgoing
+  `Accept-Encoding` header, so the codecs actually enabled in this build (gzip, plus
+  whichever of `brotli`/`deflate`/`zstd` are feature-enabled) are the ones advertised.
+* `ClientBuilder::decompression_limit(Option<usize>)` (and a per-request override) should
+  thread its value into [`Decoder::from_encodings`]/[`Decoder::detect`]'s `decompression_limit`
+  parameter; [`DEFAULT_DECOMPRESSION_LIMIT`] is the default when a caller hasn't overridden it.
+* `ClientBuilder`'s blocking-offload opt-in should thread into the `offload_to_blocking_pool`
+  parameter of the same two constructors.
+* `Response::trailers()` should return [`Decoder::trailers()`] once the body stream backing
+  the response has been driven to completion.
+
+None of `Client`, `ClientBuilder`, or `Response` exist in this checkout (there's no `lib.rs`,
+`client.rs`, `response.rs`, or `Cargo.toml` to declare the `brotli`/`deflate`/`zstd` features
+against), so that wiring can't be added here without inventing those modules' structure from
+whole cloth. The hooks above are written so connecting them is a small, mechanical change once
+those files exist.
+
+None of the five backlog items this series implements are reachable by a consumer of this
+crate yet, and shouldn't be treated as closed out until that follow-up wiring lands: a
+dedicated PR adding `Cargo.toml`'s feature flags and the `Client`/`ClientBuilder`/`Response`
+call sites above is needed before this work is user-facing.
 */
 
 use std::fmt;
 use std::future::Future;
 use std::mem;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 
 use bytes::Bytes;
+use flate2::write::GzDecoder as SyncGzDecoder;
 use futures::Stream;
-use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
 use hyper::HeaderMap;
 
 use log::warn;
+use tokio::task::JoinHandle;
 
 use super::{Body, Chunk};
 use crate::error;
 
+/// Trailer headers captured once the body's `Stream` reaches end-of-stream.
+///
+/// Shared between the `Decoder` and whichever `BodyBytes` ends up driving the body, since
+/// the body may be buried several decoders deep by the time it's exhausted.
+type Trailers = Arc<Mutex<Option<HeaderMap>>>;
+
+/// The default cap on decompressed bytes a `Decoder` will emit, used when a request doesn't
+/// override it. Generous enough for almost any legitimate response, while still bounding how
+/// far a malicious one can inflate.
+pub(crate) const DEFAULT_DECOMPRESSION_LIMIT: usize = 256 * 1024 * 1024;
+
 /// A response decompressor over a non-blocking stream of chunks.
 ///
 /// The inner decoder may be constructed asynchronously.
 pub struct Decoder {
     inner: Inner,
+    trailers: Trailers,
+    /// Cap on the total number of decompressed bytes this decoder will emit, or `None` to
+    /// decompress without limit.
+    limit: Option<usize>,
+    /// Running count of decompressed bytes produced so far, including a chunk rejected for
+    /// exceeding `limit`. Only kept while `limit` is set; it stays at 0 otherwise.
+    decompressed_total: usize,
 }
 
 enum Inner {
     /// A `PlainText` decoder just returns the response content as is.
-    PlainText(Body),
+    PlainText(futures::stream::Peekable<BodyBytes>),
     /// A `Gzip` decoder will uncompress the gzipped response content before returning it.
-    Gzip(async_compression::stream::GzipDecoder<futures::stream::Peekable<BodyBytes>>),
+    Gzip(DrainOnEof<async_compression::stream::GzipDecoder<futures::stream::Peekable<BodyBytes>>>),
+    /// A `Brotli` decoder will uncompress the brotli-compressed response content before returning it.
+    #[cfg(feature = "brotli")]
+    Brotli(
+        DrainOnEof<async_compression::stream::BrotliDecoder<futures::stream::Peekable<BodyBytes>>>,
+    ),
+    /// A `Deflate` decoder will uncompress the deflate-compressed response content before returning it.
+    #[cfg(feature = "deflate")]
+    Deflate(
+        DrainOnEof<async_compression::stream::ZlibDecoder<futures::stream::Peekable<BodyBytes>>>,
+    ),
+    /// A `Zstd` decoder will uncompress the zstd-compressed response content before returning it.
+    #[cfg(feature = "zstd")]
+    Zstd(DrainOnEof<async_compression::stream::ZstdDecoder<futures::stream::Peekable<BodyBytes>>>),
+    /// A `Stacked` decoder chains two or more codecs together, peeling them off in the
+    /// reverse of the order they were listed in `Content-Encoding`.
+    Stacked(BoxedBytesStream),
+    /// A `Blocking` decoder runs gzip decompression on a `spawn_blocking` task instead of
+    /// inline, to keep CPU-bound inflation off the async executor.
+    Blocking(Blocking),
     /// A decoder that doesn't have a value yet.
     Pending(Pending),
 }
 
-/// A future attempt to poll the response body for EOF so we know whether to use gzip or not.
-struct Pending(futures::stream::Peekable<BodyBytes>);
+/// A boxed, already-decoding stream of raw bytes, used to chain multiple codecs together.
+type BoxedBytesStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>;
+
+/// A codec decoder that can hand back its inner stream, so it can be polled directly.
+trait DecoderStream: Stream<Item = Result<Bytes, std::io::Error>> {
+    type Input: Stream<Item = Result<Bytes, std::io::Error>> + Unpin;
+
+    fn input_mut(&mut self) -> &mut Self::Input;
+}
+
+macro_rules! impl_decoder_stream {
+    ($($ty:ident),+ $(,)?) => {
+        $(
+            impl<S> DecoderStream for async_compression::stream::$ty<S>
+            where
+                S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin,
+            {
+                type Input = S;
 
-struct BodyBytes(Body);
+                fn input_mut(&mut self) -> &mut S {
+                    self.get_mut()
+                }
+            }
+        )+
+    };
+}
+
+impl_decoder_stream!(GzipDecoder);
+#[cfg(feature = "brotli")]
+impl_decoder_stream!(BrotliDecoder);
+#[cfg(feature = "deflate")]
+impl_decoder_stream!(ZlibDecoder);
+#[cfg(feature = "zstd")]
+impl_decoder_stream!(ZstdDecoder);
+
+/// Wraps a codec decoder so that, once it signals end-of-stream, its inner stream keeps
+/// getting polled until *that* also reaches end-of-stream.
+///
+/// `async_compression`'s decoders stop polling their inner stream as soon as they've
+/// decoded a complete payload out of already-buffered input; they don't necessarily poll
+/// once more to observe the inner stream's own `None`. Since trailer headers are only
+/// captured by `BodyBytes` when it's polled past the raw body's end, a decoder that never
+/// makes that extra poll means trailers never get captured for compressed responses. This
+/// drives that extra poll (and any further ones, for decoders stacked more than one deep)
+/// so trailer capture happens regardless of how early the codec stops reading.
+struct DrainOnEof<D> {
+    decoder: D,
+    decoder_done: bool,
+}
+
+impl<D> DrainOnEof<D> {
+    fn new(decoder: D) -> DrainOnEof<D> {
+        DrainOnEof {
+            decoder,
+            decoder_done: false,
+        }
+    }
+}
+
+impl<D> Stream for DrainOnEof<D>
+where
+    D: DecoderStream + Unpin,
+{
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if !this.decoder_done {
+            match futures::ready!(Pin::new(&mut this.decoder).poll_next(cx)) {
+                Some(item) => return Poll::Ready(Some(item)),
+                None => this.decoder_done = true,
+            }
+        }
+
+        loop {
+            match futures::ready!(Pin::new(this.decoder.input_mut()).poll_next(cx)) {
+                Some(_) => continue,
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// The compression codec a `Pending` decoder will build once the body is available.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// Parses a single `Content-Encoding`/`Transfer-Encoding` token, if it names a codec
+    /// this build supports. `identity` is handled separately by the caller, since it's a
+    /// no-op rather than a codec.
+    fn parse(token: &str) -> Option<Encoding> {
+        if token.eq_ignore_ascii_case("gzip") {
+            return Some(Encoding::Gzip);
+        }
+        #[cfg(feature = "brotli")]
+        {
+            if token.eq_ignore_ascii_case("br") {
+                return Some(Encoding::Brotli);
+            }
+        }
+        #[cfg(feature = "deflate")]
+        {
+            if token.eq_ignore_ascii_case("deflate") {
+                return Some(Encoding::Deflate);
+            }
+        }
+        #[cfg(feature = "zstd")]
+        {
+            if token.eq_ignore_ascii_case("zstd") {
+                return Some(Encoding::Zstd);
+            }
+        }
+        None
+    }
+
+    /// The `Accept-Encoding` token this codec is advertised as.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps `input` in a decoder for this codec, boxing the result so it can be
+    /// fed into the next decoder in a stack.
+    fn wrap(self, input: BoxedBytesStream) -> BoxedBytesStream {
+        match self {
+            Encoding::Gzip => Box::pin(DrainOnEof::new(
+                async_compression::stream::GzipDecoder::new(input),
+            )),
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Box::pin(DrainOnEof::new(
+                async_compression::stream::BrotliDecoder::new(input),
+            )),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Box::pin(DrainOnEof::new(
+                async_compression::stream::ZlibDecoder::new(input),
+            )),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Box::pin(DrainOnEof::new(
+                async_compression::stream::ZstdDecoder::new(input),
+            )),
+        }
+    }
+}
+
+/// Builds the `Accept-Encoding` header value advertising every codec this build supports.
+pub(crate) fn accept_encoding_value() -> HeaderValue {
+    let mut tokens: Vec<&str> = vec![Encoding::Gzip.as_str()];
+    #[cfg(feature = "brotli")]
+    tokens.push(Encoding::Brotli.as_str());
+    #[cfg(feature = "deflate")]
+    tokens.push(Encoding::Deflate.as_str());
+    #[cfg(feature = "zstd")]
+    tokens.push(Encoding::Zstd.as_str());
+
+    // unwrap is safe: the tokens are our own ASCII codec names, joined with ", ".
+    HeaderValue::from_str(&tokens.join(", ")).unwrap()
+}
+
+/// The outcome of parsing a `Content-Encoding` header's token list: the codecs to peel off,
+/// in the order they should be decoded (the reverse of how they're listed), and whatever
+/// tokens are left over because decoding couldn't reach them.
+struct ParsedEncodings {
+    /// Codecs to decode, outermost (decoded first) to innermost (decoded last).
+    decode_order: Vec<Encoding>,
+    /// Tokens, in their original order, that were not consumed and must be left on the
+    /// response so downstream code still sees them.
+    remaining: Vec<String>,
+}
+
+/// Parses every `Content-Encoding` header line, splitting on commas, and walks the resulting
+/// token list from the end (the outermost encoding) towards the start. `identity` tokens are
+/// no-ops and are simply dropped. The first unrecognized token stops the walk: it and every
+/// token before it are left in `remaining`, since we have no way to peel them off.
+fn parse_content_encodings<'a>(values: impl Iterator<Item = &'a HeaderValue>) -> ParsedEncodings {
+    let tokens: Vec<&str> = values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tok| tok.trim())
+        .filter(|tok| !tok.is_empty())
+        .collect();
+
+    let mut decode_order = Vec::new();
+    let mut split_at = 0;
+    for (i, token) in tokens.iter().enumerate().rev() {
+        if token.eq_ignore_ascii_case("identity") {
+            continue;
+        }
+        match Encoding::parse(token) {
+            Some(encoding) => decode_order.push(encoding),
+            None => {
+                split_at = i + 1;
+                break;
+            }
+        }
+    }
+
+    let remaining = tokens[..split_at].iter().map(|s| s.to_string()).collect();
+    ParsedEncodings {
+        decode_order,
+        remaining,
+    }
+}
+
+/// A synchronous gzip decoder, stepped from inside a `spawn_blocking` task.
+///
+/// Wraps `flate2`'s `Write`-based decoder, feeding it one compressed chunk at a time and
+/// draining whatever decompressed output that produced.
+struct SyncGzipDecoder {
+    inner: SyncGzDecoder<Vec<u8>>,
+}
+
+impl SyncGzipDecoder {
+    fn new() -> SyncGzipDecoder {
+        SyncGzipDecoder {
+            inner: SyncGzDecoder::new(Vec::new()),
+        }
+    }
+
+    fn decompress(&mut self, input: Bytes) -> std::io::Result<Bytes> {
+        use std::io::Write;
+
+        self.inner.write_all(&input)?;
+        // `write_all` only feeds flate2's internal buffer; it doesn't push decompressed
+        // output through to the `Vec<u8>` writer until told to. Without this, draining
+        // `get_mut()` below recovers little or nothing of what was just decoded.
+        self.inner.flush()?;
+        Ok(Bytes::from(mem::take(self.inner.get_mut())))
+    }
+
+    /// Finalizes decoding once the compressed input is exhausted, returning any
+    /// remaining decompressed bytes.
+    ///
+    /// This validates the gzip trailer (the CRC32 and length checksum), so a response
+    /// that was cut off mid-stream surfaces as an error here instead of silently
+    /// yielding truncated output.
+    fn finish(&mut self) -> std::io::Result<Bytes> {
+        self.inner.try_finish()?;
+        Ok(Bytes::from(mem::take(self.inner.get_mut())))
+    }
+}
+
+/// State for the blocking-offload decoder: the compressed input, the synchronous decoder
+/// (taken out while a blocking step is in flight), and that step's `JoinHandle`, if any.
+struct Blocking {
+    input: futures::stream::Peekable<BodyBytes>,
+    decoder: Option<SyncGzipDecoder>,
+    task: Option<JoinHandle<std::io::Result<(SyncGzipDecoder, Bytes)>>>,
+    input_done: bool,
+    /// Set once the final `decoder.finish()` step has been dispatched, so we only do it once.
+    finished: bool,
+}
+
+/// Drives a `Blocking` decoder: feeds it more compressed input and spawns a blocking step
+/// whenever one isn't already in flight, polling that step to completion otherwise.
+fn poll_blocking(
+    state: &mut Blocking,
+    cx: &mut Context<'_>,
+    decompressed_total: &mut usize,
+    limit: Option<usize>,
+) -> Poll<Option<Result<Chunk, error::Error>>> {
+    loop {
+        if let Some(task) = state.task.as_mut() {
+            let result = futures::ready!(Pin::new(task).poll(cx));
+            state.task = None;
+            match result {
+                Ok(Ok((decoder, bytes))) => {
+                    state.decoder = Some(decoder);
+                    if bytes.is_empty() {
+                        // The blocking step consumed input without producing output yet;
+                        // that isn't EOF unless the input stream itself is done.
+                        if state.input_done {
+                            return Poll::Ready(None);
+                        }
+                        continue;
+                    }
+                    if let Err(e) =
+                        check_decompression_limit(decompressed_total, limit, bytes.len())
+                    {
+                        return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                    }
+                    return Poll::Ready(Some(Ok(bytes.into())));
+                }
+                Ok(Err(e)) => return Poll::Ready(Some(Err(crate::error::from_io(e)))),
+                Err(join_err) => {
+                    return Poll::Ready(Some(Err(crate::error::from_io(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        join_err,
+                    )))))
+                }
+            }
+        }
+
+        if state.input_done {
+            if state.finished {
+                return Poll::Ready(None);
+            }
+            // The compressed input is exhausted; run one last blocking step to flush out
+            // anything flate2 was still holding and to validate the gzip trailer, so a
+            // response truncated mid-stream surfaces as an error rather than silently
+            // returning partial output.
+            let mut decoder = state
+                .decoder
+                .take()
+                .expect("blocking decoder state missing while no task is in flight");
+            state.finished = true;
+            state.task = Some(tokio::task::spawn_blocking(move || {
+                let out = decoder.finish()?;
+                Ok((decoder, out))
+            }));
+            continue;
+        }
+
+        match futures::ready!(Pin::new(&mut state.input).poll_next(cx)) {
+            Some(Ok(bytes)) => {
+                let mut decoder = state
+                    .decoder
+                    .take()
+                    .expect("blocking decoder state missing while no task is in flight");
+                state.task = Some(tokio::task::spawn_blocking(move || {
+                    let out = decoder.decompress(bytes)?;
+                    Ok((decoder, out))
+                }));
+            }
+            Some(Err(err)) => return Poll::Ready(Some(Err(crate::error::from_io(err)))),
+            None => state.input_done = true,
+        }
+    }
+}
+
+/// A future attempt to poll the response body for EOF so we know whether to use a decoder or not.
+struct Pending(futures::stream::Peekable<BodyBytes>, Vec<Encoding>, bool);
+
+/// Wraps a `Body`, converting its chunks to plain `Bytes` and, once the body ends, polling
+/// for trailers and stashing them in the shared `Trailers` slot.
+struct BodyBytes {
+    body: Body,
+    trailers: Trailers,
+    done: bool,
+    /// Set once trailers have been polled for, so later polls (e.g. from `DrainOnEof`
+    /// driving this past its own `None`) don't poll `Body::poll_trailers` again.
+    trailers_polled: bool,
+}
+
+impl BodyBytes {
+    fn new(body: Body, trailers: Trailers) -> BodyBytes {
+        BodyBytes {
+            body,
+            trailers,
+            done: false,
+            trailers_polled: false,
+        }
+    }
+}
 
 impl fmt::Debug for Decoder {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -60,8 +507,14 @@ impl Decoder {
     /// This decoder will produce a single 0 byte chunk.
     #[inline]
     pub fn empty() -> Decoder {
+        use futures::stream::StreamExt;
+
+        let trailers = Trailers::default();
         Decoder {
-            inner: Inner::PlainText(Body::empty()),
+            inner: Inner::PlainText(BodyBytes::new(Body::empty(), trailers.clone()).peekable()),
+            trailers,
+            limit: None,
+            decompressed_total: 0,
         }
     }
 
@@ -69,87 +522,247 @@ impl Decoder {
     ///
     /// This decoder will emit the underlying chunks as-is.
     fn plain_text(body: Body) -> Decoder {
+        use futures::stream::StreamExt;
+
+        let trailers = Trailers::default();
         Decoder {
-            inner: Inner::PlainText(body),
+            inner: Inner::PlainText(BodyBytes::new(body, trailers.clone()).peekable()),
+            trailers,
+            limit: None,
+            decompressed_total: 0,
         }
     }
 
-    /// A gzip decoder.
+    /// A decoder for one or more stacked compression codecs.
     ///
-    /// This decoder will buffer and decompress chunks that are gzipped.
-    fn gzip(body: Body) -> Decoder {
+    /// This decoder will buffer and decompress chunks, peeling off `decode_order` from
+    /// front to back. `limit` caps the total number of decompressed bytes that may be
+    /// emitted before the stream fails, or disables the cap if `None`. `offload_to_blocking_pool`
+    /// runs a lone gzip codec's decompression on a blocking task instead of inline.
+    fn from_encodings(
+        decode_order: Vec<Encoding>,
+        body: Body,
+        limit: Option<usize>,
+        offload_to_blocking_pool: bool,
+    ) -> Decoder {
         use futures::stream::StreamExt;
 
+        let trailers = Trailers::default();
         Decoder {
-            inner: Inner::Pending(Pending(BodyBytes(body).peekable())),
+            inner: Inner::Pending(Pending(
+                BodyBytes::new(body, trailers.clone()).peekable(),
+                decode_order,
+                offload_to_blocking_pool,
+            )),
+            trailers,
+            limit,
+            decompressed_total: 0,
         }
     }
 
+    /// Returns the trailer headers sent after the final chunk of the body, if the body has
+    /// finished and any were sent.
+    ///
+    /// This is only populated once the decoder's `Stream` impl has yielded `None`.
+    pub(crate) fn trailers(&self) -> Option<HeaderMap> {
+        self.trailers.lock().ok().and_then(|guard| guard.clone())
+    }
+
     /// Constructs a Decoder from a hyper request.
     ///
     /// A decoder is just a wrapper around the hyper request that knows
     /// how to decode the content body of the request.
     ///
     /// Uses the correct variant by inspecting the Content-Encoding header.
-    pub(crate) fn detect(headers: &mut HeaderMap, body: Body, check_gzip: bool) -> Decoder {
+    ///
+    /// `decompression_limit` caps the total number of decompressed bytes the resulting
+    /// decoder will emit; pass `None` to decompress without limit. `offload_to_blocking_pool`
+    /// runs gzip decompression on a blocking task instead of inline when gzip is the only
+    /// codec to decode; other codecs and stacked encodings are always decoded inline.
+    pub(crate) fn detect(
+        headers: &mut HeaderMap,
+        body: Body,
+        check_gzip: bool,
+        decompression_limit: Option<usize>,
+        offload_to_blocking_pool: bool,
+    ) -> Decoder {
         if !check_gzip {
             return Decoder::plain_text(body);
         }
-        let content_encoding_gzip: bool;
-        let mut is_gzip = {
-            content_encoding_gzip = headers
-                .get_all(CONTENT_ENCODING)
-                .iter()
-                .any(|enc| enc == "gzip");
-            content_encoding_gzip
-                || headers
-                    .get_all(TRANSFER_ENCODING)
-                    .iter()
-                    .any(|enc| enc == "gzip")
-        };
-        if is_gzip {
-            if let Some(content_length) = headers.get(CONTENT_LENGTH) {
-                if content_length == "0" {
-                    warn!("gzip response with content-length of 0");
-                    is_gzip = false;
-                }
+
+        let parsed = parse_content_encodings(headers.get_all(CONTENT_ENCODING).iter());
+
+        let decode_order = if !parsed.decode_order.is_empty() {
+            let content_length_zero = headers.get(CONTENT_LENGTH).map_or(false, |len| len == "0");
+
+            // Strip the headers we're about to act on unconditionally, same as the baseline
+            // did: whether we end up decoding or bailing out below, a consumer shouldn't see
+            // stale Content-Encoding/-Length describing bytes we already consumed.
+            if parsed.remaining.is_empty() {
+                headers.remove(CONTENT_ENCODING);
+            } else {
+                // unwrap is safe: these are tokens we just parsed out of a valid header value.
+                headers.insert(
+                    CONTENT_ENCODING,
+                    HeaderValue::from_str(&parsed.remaining.join(", ")).unwrap(),
+                );
             }
-        }
-        if content_encoding_gzip {
-            headers.remove(CONTENT_ENCODING);
             headers.remove(CONTENT_LENGTH);
-        }
-        if is_gzip {
-            Decoder::gzip(body)
+
+            if content_length_zero {
+                warn!("compressed response with content-length of 0");
+                return Decoder::plain_text(body);
+            }
+
+            parsed.decode_order
         } else {
-            Decoder::plain_text(body)
+            // No usable Content-Encoding; fall back to a single Transfer-Encoding token, as
+            // chunked transfer coding stacking is vanishingly rare in practice.
+            match headers
+                .get_all(TRANSFER_ENCODING)
+                .iter()
+                .find_map(|enc| Encoding::parse(enc.to_str().unwrap_or_default()))
+            {
+                Some(encoding) => vec![encoding],
+                None => return Decoder::plain_text(body),
+            }
+        };
+
+        Decoder::from_encodings(
+            decode_order,
+            body,
+            decompression_limit,
+            offload_to_blocking_pool,
+        )
+    }
+}
+
+/// With a `Some` limit, adds `len` decompressed bytes to `total`, failing if that pushes it
+/// past the limit. With `None`, does nothing and leaves `total` unchanged.
+fn check_decompression_limit(
+    total: &mut usize,
+    limit: Option<usize>,
+    len: usize,
+) -> Result<(), std::io::Error> {
+    if let Some(limit) = limit {
+        *total += len;
+        if *total > limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "response body exceeded the configured decompression limit",
+            ));
         }
     }
+    Ok(())
 }
 
 impl Stream for Decoder {
     type Item = Result<Chunk, error::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // `Decoder` is `Unpin`, so `self.inner`/`self.limit`/`self.decompressed_total` each
+        // reborrow through `Pin::deref_mut` on access; matching on one field while reading
+        // another back out of the same `self` conflicts under the borrow checker. Get a plain
+        // `&mut Decoder` once up front and pull out the pieces the codec arms need as locals
+        // before matching on `this.inner`.
+        let this = self.get_mut();
+        let limit = this.limit;
+        let decompressed_total = &mut this.decompressed_total;
+
         // Do a read or poll for a pending decoder value.
-        let new_value = match self.inner {
+        let new_value = match this.inner {
             Inner::Pending(ref mut future) => match Pin::new(future).poll(cx) {
                 Poll::Ready(Ok(inner)) => inner,
                 Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(crate::error::from_io(e)))),
                 Poll::Pending => return Poll::Pending,
             },
-            Inner::PlainText(ref mut body) => return Pin::new(body).poll_next(cx),
+            Inner::PlainText(ref mut body) => {
+                return match futures::ready!(Pin::new(body).poll_next(cx)) {
+                    Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.into()))),
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
             Inner::Gzip(ref mut decoder) => {
                 return match futures::ready!(Pin::new(decoder).poll_next(cx)) {
-                    Some(Ok(bytes)) => Poll::Ready(Some(Ok(bytes.into()))),
+                    Some(Ok(bytes)) => {
+                        if let Err(e) =
+                            check_decompression_limit(decompressed_total, limit, bytes.len())
+                        {
+                            return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                        }
+                        Poll::Ready(Some(Ok(bytes.into())))
+                    }
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
+            #[cfg(feature = "brotli")]
+            Inner::Brotli(ref mut decoder) => {
+                return match futures::ready!(Pin::new(decoder).poll_next(cx)) {
+                    Some(Ok(bytes)) => {
+                        if let Err(e) =
+                            check_decompression_limit(decompressed_total, limit, bytes.len())
+                        {
+                            return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                        }
+                        Poll::Ready(Some(Ok(bytes.into())))
+                    }
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
+            #[cfg(feature = "deflate")]
+            Inner::Deflate(ref mut decoder) => {
+                return match futures::ready!(Pin::new(decoder).poll_next(cx)) {
+                    Some(Ok(bytes)) => {
+                        if let Err(e) =
+                            check_decompression_limit(decompressed_total, limit, bytes.len())
+                        {
+                            return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                        }
+                        Poll::Ready(Some(Ok(bytes.into())))
+                    }
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(ref mut decoder) => {
+                return match futures::ready!(Pin::new(decoder).poll_next(cx)) {
+                    Some(Ok(bytes)) => {
+                        if let Err(e) =
+                            check_decompression_limit(decompressed_total, limit, bytes.len())
+                        {
+                            return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                        }
+                        Poll::Ready(Some(Ok(bytes.into())))
+                    }
+                    Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
+                    None => Poll::Ready(None),
+                }
+            }
+            Inner::Stacked(ref mut stream) => {
+                return match futures::ready!(Pin::new(stream).poll_next(cx)) {
+                    Some(Ok(bytes)) => {
+                        if let Err(e) =
+                            check_decompression_limit(decompressed_total, limit, bytes.len())
+                        {
+                            return Poll::Ready(Some(Err(crate::error::from_io(e))));
+                        }
+                        Poll::Ready(Some(Ok(bytes.into())))
+                    }
                     Some(Err(err)) => Poll::Ready(Some(Err(crate::error::from_io(err)))),
                     None => Poll::Ready(None),
                 }
             }
+            Inner::Blocking(ref mut state) => {
+                return poll_blocking(state, cx, decompressed_total, limit)
+            }
         };
 
-        self.inner = new_value;
-        self.poll_next(cx)
+        this.inner = new_value;
+        Pin::new(this).poll_next(cx)
     }
 }
 
@@ -169,13 +782,56 @@ impl Future for Pending {
                     .expect("just peeked Some")
                     .unwrap_err()));
             }
-            None => return Poll::Ready(Ok(Inner::PlainText(Body::empty()))),
+            None => {
+                // Reuse the real, already-peeked body instead of substituting a fresh,
+                // disconnected empty one: it shares the `Trailers` slot the `Decoder` reads
+                // from, and peeking it to `None` already drove it to poll for trailers.
+                let body = mem::replace(
+                    &mut self.0,
+                    BodyBytes::new(Body::empty(), Trailers::default()).peekable(),
+                );
+                return Poll::Ready(Ok(Inner::PlainText(body)));
+            }
         };
 
-        let body = mem::replace(&mut self.0, BodyBytes(Body::empty()).peekable());
-        Poll::Ready(Ok(Inner::Gzip(
-            async_compression::stream::GzipDecoder::new(body),
-        )))
+        let body = mem::replace(
+            &mut self.0,
+            BodyBytes::new(Body::empty(), Trailers::default()).peekable(),
+        );
+
+        Poll::Ready(Ok(if self.2 && self.1.as_slice() == [Encoding::Gzip] {
+            Inner::Blocking(Blocking {
+                input: body,
+                decoder: Some(SyncGzipDecoder::new()),
+                task: None,
+                input_done: false,
+                finished: false,
+            })
+        } else if self.1.len() == 1 {
+            match self.1[0] {
+                Encoding::Gzip => Inner::Gzip(DrainOnEof::new(
+                    async_compression::stream::GzipDecoder::new(body),
+                )),
+                #[cfg(feature = "brotli")]
+                Encoding::Brotli => Inner::Brotli(DrainOnEof::new(
+                    async_compression::stream::BrotliDecoder::new(body),
+                )),
+                #[cfg(feature = "deflate")]
+                Encoding::Deflate => Inner::Deflate(DrainOnEof::new(
+                    async_compression::stream::ZlibDecoder::new(body),
+                )),
+                #[cfg(feature = "zstd")]
+                Encoding::Zstd => Inner::Zstd(DrainOnEof::new(
+                    async_compression::stream::ZstdDecoder::new(body),
+                )),
+            }
+        } else {
+            let mut stream: BoxedBytesStream = Box::pin(body);
+            for encoding in &self.1 {
+                stream = encoding.wrap(stream);
+            }
+            Inner::Stacked(stream)
+        }))
     }
 }
 
@@ -183,10 +839,291 @@ impl Stream for BodyBytes {
     type Item = Result<Bytes, std::io::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        match futures::ready!(Pin::new(&mut self.0).poll_next(cx)) {
-            Some(Ok(chunk)) => Poll::Ready(Some(Ok(chunk.into()))),
-            Some(Err(err)) => Poll::Ready(Some(Err(err.into_io()))),
-            None => Poll::Ready(None),
+        let this = &mut *self;
+
+        if !this.done {
+            match futures::ready!(Pin::new(&mut this.body).poll_next(cx)) {
+                Some(Ok(chunk)) => return Poll::Ready(Some(Ok(chunk.into()))),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into_io()))),
+                None => this.done = true,
+            }
+        }
+
+        if this.trailers_polled {
+            return Poll::Ready(None);
+        }
+
+        // The body's data is exhausted; grab any trailers before signalling our own EOF.
+        match futures::ready!(Pin::new(&mut this.body).poll_trailers(cx)) {
+            Ok(trailers) => {
+                this.trailers_polled = true;
+                if let Ok(mut slot) = this.trailers.lock() {
+                    *slot = trailers;
+                }
+                Poll::Ready(None)
+            }
+            Err(err) => Poll::Ready(Some(Err(err.into_io()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_content_encodings_stacks_in_reverse_and_skips_identity() {
+        // Two header lines, as multiple `Content-Encoding` lines or a single comma-joined
+        // one would both produce: `gzip, identity` then `gzip`.
+        let values = [
+            HeaderValue::from_static("gzip, identity"),
+            HeaderValue::from_static("gzip"),
+        ];
+
+        let parsed = parse_content_encodings(values.iter());
+
+        // Decoded in reverse of how they're listed: the last-listed `gzip` first, then the
+        // first-listed one; `identity` is a no-op and contributes nothing.
+        assert_eq!(parsed.decode_order, vec![Encoding::Gzip, Encoding::Gzip]);
+        assert!(parsed.remaining.is_empty());
+    }
+
+    #[test]
+    fn parse_content_encodings_stops_at_the_first_unknown_token() {
+        let values = [HeaderValue::from_static("gzip, bogus-token, gzip")];
+
+        let parsed = parse_content_encodings(values.iter());
+
+        // Only the trailing, recognized `gzip` gets decoded; `bogus-token` and everything
+        // before it are left behind since there's no way to peel them off.
+        assert_eq!(parsed.decode_order, vec![Encoding::Gzip]);
+        assert_eq!(parsed.remaining, vec!["gzip", "bogus-token"]);
+    }
+
+    #[test]
+    fn check_decompression_limit_accumulates_and_trips_once_exceeded() {
+        let mut total = 0;
+
+        check_decompression_limit(&mut total, Some(100), 40).unwrap();
+        check_decompression_limit(&mut total, Some(100), 40).unwrap();
+        assert_eq!(total, 80);
+
+        // Crossing the limit on this call should fail...
+        assert!(check_decompression_limit(&mut total, Some(100), 21).is_err());
+        // ...but the running total still reflects every byte seen so far.
+        assert_eq!(total, 101);
+    }
+
+    #[test]
+    fn check_decompression_limit_disabled_with_none() {
+        let mut total = 0;
+        check_decompression_limit(&mut total, None, usize::MAX / 2).unwrap();
+        check_decompression_limit(&mut total, None, usize::MAX / 2).unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn accept_encoding_value_advertises_every_enabled_codec() {
+        let value = accept_encoding_value().to_str().unwrap().to_string();
+        let tokens: Vec<&str> = value.split(", ").collect();
+
+        assert!(tokens.contains(&"gzip"));
+        #[cfg(feature = "brotli")]
+        assert!(tokens.contains(&"br"));
+        #[cfg(feature = "deflate")]
+        assert!(tokens.contains(&"deflate"));
+        #[cfg(feature = "zstd")]
+        assert!(tokens.contains(&"zstd"));
+    }
+
+    #[test]
+    fn sync_gzip_decoder_recovers_all_bytes_without_flush_loss() {
+        let original = vec![b'a'; 64 * 1024];
+        let compressed = gzip(&original);
+
+        // Split the compressed payload across multiple `decompress` calls, as chunks
+        // arriving over the wire would be, to exercise the flush path repeatedly.
+        let mut decoder = SyncGzipDecoder::new();
+        let mut out = Vec::new();
+        for chunk in compressed.chunks(37) {
+            out.extend_from_slice(&decoder.decompress(Bytes::copy_from_slice(chunk)).unwrap());
+        }
+        out.extend_from_slice(&decoder.finish().unwrap());
+
+        assert_eq!(out, original);
+    }
+
+    #[test]
+    fn sync_gzip_decoder_errors_on_truncated_input() {
+        let compressed = gzip(&vec![b'a'; 64 * 1024]);
+        let truncated = &compressed[..compressed.len() - 8];
+
+        let mut decoder = SyncGzipDecoder::new();
+        decoder
+            .decompress(Bytes::copy_from_slice(truncated))
+            .unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    /// A stream that yields `remaining` empty chunks before returning `None`.
+    struct CountingStream {
+        remaining: usize,
+    }
+
+    impl Stream for CountingStream {
+        type Item = Result<Bytes, std::io::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                Poll::Ready(Some(Ok(Bytes::new())))
+            } else {
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    /// Mimics `async_compression`'s decoders: stops polling its inner stream as soon as it's
+    /// produced its one decoded chunk, without ever observing the inner stream's own `None`.
+    struct EarlyStoppingDecoder {
+        inner: CountingStream,
+        yielded: bool,
+    }
+
+    impl Stream for EarlyStoppingDecoder {
+        type Item = Result<Bytes, std::io::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+            if !self.yielded {
+                self.yielded = true;
+                Poll::Ready(Some(Ok(Bytes::from_static(b"decoded"))))
+            } else {
+                Poll::Ready(None)
+            }
         }
     }
+
+    impl DecoderStream for EarlyStoppingDecoder {
+        type Input = CountingStream;
+
+        fn input_mut(&mut self) -> &mut CountingStream {
+            &mut self.inner
+        }
+    }
+
+    #[test]
+    fn drain_on_eof_polls_inner_stream_to_its_own_eof() {
+        let decoder = EarlyStoppingDecoder {
+            inner: CountingStream { remaining: 2 },
+            yielded: false,
+        };
+        let mut drain = DrainOnEof::new(decoder);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(matches!(
+            Pin::new(&mut drain).poll_next(&mut cx),
+            Poll::Ready(Some(Ok(_)))
+        ));
+        // The decoder itself never polls its inner stream again, but `DrainOnEof` should
+        // keep polling it (discarding leftover items) until it also reaches `None` - that's
+        // what lets `BodyBytes` further downstream observe end-of-stream and capture trailers.
+        assert!(matches!(
+            Pin::new(&mut drain).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+        assert_eq!(drain.decoder.inner.remaining, 0);
+    }
+
+    /// Drives `poll_blocking` to completion on `rt`, retrying on `Poll::Pending` by yielding
+    /// so the in-flight `spawn_blocking` task gets a chance to run.
+    fn drain_blocking(
+        rt: &tokio::runtime::Runtime,
+        state: &mut Blocking,
+        total: &mut usize,
+        limit: Option<usize>,
+    ) -> Vec<Result<Chunk, error::Error>> {
+        rt.block_on(async {
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut out = Vec::new();
+            loop {
+                match poll_blocking(state, &mut cx, total, limit) {
+                    Poll::Ready(None) => return out,
+                    Poll::Ready(Some(result)) => out.push(result),
+                    Poll::Pending => tokio::task::yield_now().await,
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn poll_blocking_retries_instead_of_stopping_when_a_step_yields_no_bytes_yet() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        // `Body::empty()` is already relied on elsewhere in this file (`Decoder::empty()`,
+        // `Pending`'s placeholder body), so it's a safe stand-in for "no more compressed
+        // input" here: it resolves to `None` on the very next poll.
+        let mut state = Blocking {
+            input: BodyBytes::new(Body::empty(), Trailers::default()).peekable(),
+            decoder: Some(SyncGzipDecoder::new()),
+            task: None,
+            input_done: false,
+            finished: false,
+        };
+
+        // Seed an in-flight task that finishes having consumed input but produced no
+        // decompressed bytes yet - the case the backlog calls out explicitly. With
+        // `input_done` still `false`, `poll_blocking` must loop back around to poll
+        // `state.input` for more, not treat the empty result as end-of-stream.
+        let decoder = state.decoder.take().unwrap();
+        let _guard = rt.enter();
+        state.task = Some(tokio::task::spawn_blocking(move || {
+            Ok((decoder, Bytes::new()))
+        }));
+        drop(_guard);
+
+        let mut total = 0;
+        let chunks = drain_blocking(&rt, &mut state, &mut total, None);
+
+        // `Body::empty()` has no data, so once the seeded no-bytes-yet step is past, the
+        // input is immediately exhausted; `poll_blocking` still runs the finalize step
+        // before ever signalling completion.
+        assert!(chunks.is_empty());
+        assert!(state.input_done);
+        assert!(state.finished);
+    }
+
+    #[test]
+    fn poll_blocking_signals_completion_once_and_stays_done() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut state = Blocking {
+            input: BodyBytes::new(Body::empty(), Trailers::default()).peekable(),
+            decoder: Some(SyncGzipDecoder::new()),
+            task: None,
+            input_done: false,
+            finished: false,
+        };
+
+        let mut total = 0;
+        assert!(drain_blocking(&rt, &mut state, &mut total, None).is_empty());
+
+        // Polling again afterwards must not re-dispatch a second finalize task.
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            poll_blocking(&mut state, &mut cx, &mut total, None),
+            Poll::Ready(None)
+        ));
+    }
 }